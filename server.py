@@ -10,8 +10,19 @@ Features:
 - Thinking levels: minimal, medium, high
 """
 
+import json
+import math
 import os
+import random
 import re
+import sys
+import tempfile
+import threading
+import time
+import urllib.request
+import uuid
+from pathlib import Path
+from urllib.parse import quote
 from typing import Optional, Literal
 from fastmcp import FastMCP
 from mcp.types import ToolAnnotations
@@ -21,6 +32,8 @@ from dotenv import load_dotenv
 # Load environment variables
 load_dotenv()
 
+ThinkingLevel = Literal["minimal", "low", "medium", "high"]
+
 # Initialize FastMCP server
 mcp = FastMCP("Gemini Research")
 
@@ -31,6 +44,13 @@ _TOOL_ANNOTATIONS = ToolAnnotations(
     openWorldHint=True,
 )
 
+_DESTRUCTIVE_TOOL_ANNOTATIONS = ToolAnnotations(
+    readOnlyHint=False,
+    destructiveHint=True,
+    idempotentHint=True,
+    openWorldHint=True,
+)
+
 # Get API key from environment
 GEMINI_API_KEY = os.getenv("GEMINI_API_KEY")
 if not GEMINI_API_KEY:
@@ -40,90 +60,543 @@ if not GEMINI_API_KEY:
     )
 
 # API configuration
-INTERACTIONS_ENDPOINT = "https://generativelanguage.googleapis.com/v1beta/interactions"
-MODEL = "gemini-3.1-flash-lite-preview"
+DEFAULT_ENDPOINT = "https://generativelanguage.googleapis.com/v1beta/interactions"
+# Trailing slashes are dropped so paths can be joined with a single "/"
+INTERACTIONS_ENDPOINT = (os.getenv("GEMINI_ENDPOINT", "").strip() or DEFAULT_ENDPOINT).rstrip("/")
+DEFAULT_MODEL = "gemini-3.1-flash-lite-preview"
+MODEL = os.getenv("GEMINI_MODEL", DEFAULT_MODEL).strip()
+if not MODEL:
+    raise ValueError("GEMINI_MODEL must not be empty; unset it to use the default model")
+
+# USD per 1M tokens as (input, output); thinking tokens are billed as output
+MODEL_PRICING = {
+    "gemini-3.1-flash-lite-preview": (0.25, 1.50),
+    "gemini-3-flash-preview": (0.50, 3.00),
+    "gemini-3-pro-preview": (2.00, 12.00),
+}
+
+# Append an estimated cost to the token footer, optionally with custom rates.
+# A rate that is neither overridden nor in the table stays None, and no cost is shown
+SHOW_COST = os.getenv("GEMINI_SHOW_COST", "").lower() in ("1", "true", "yes")
+if os.getenv("GEMINI_INPUT_PRICE") or os.getenv("GEMINI_OUTPUT_PRICE"):
+    _input_price, _output_price = MODEL_PRICING.get(MODEL, (None, None))
+    MODEL_PRICING[MODEL] = (
+        float(os.getenv("GEMINI_INPUT_PRICE")) if os.getenv("GEMINI_INPUT_PRICE") else _input_price,
+        float(os.getenv("GEMINI_OUTPUT_PRICE")) if os.getenv("GEMINI_OUTPUT_PRICE") else _output_price,
+    )
+
+# Upper bound accepted for max_output_tokens
+MAX_OUTPUT_TOKENS = 65536
+
+# Below this budget, high thinking can use up max_output_tokens before the answer is written
+HIGH_THINKING_MIN_TOKENS = 8192
+
+# Retries for connection errors and transient HTTP statuses
+MAX_RETRIES = int(os.getenv("GEMINI_MAX_RETRIES", "3"))
+if MAX_RETRIES < 0:
+    raise ValueError(f"GEMINI_MAX_RETRIES must be zero or more (got {MAX_RETRIES})")
+RETRYABLE_STATUSES = {429, 500, 502, 503, 504}
+_retry_on_status = os.getenv("GEMINI_RETRY_ON_STATUS", "").strip()
+if _retry_on_status:
+    try:
+        RETRYABLE_STATUSES = {int(code) for code in _retry_on_status.split(",") if code.strip()}
+    except ValueError:
+        RETRYABLE_STATUSES = set()
+    # Only error statuses make sense; retrying a 2xx would throw away a good answer
+    if not RETRYABLE_STATUSES or not all(400 <= code <= 599 for code in RETRYABLE_STATUSES):
+        raise ValueError(
+            "GEMINI_RETRY_ON_STATUS must be comma-separated HTTP error statuses from 400 to 599 "
+            f"(got {_retry_on_status!r})"
+        )
+
+# Cap on seconds per API call across all retries and however slowly the body arrives; 0 disables it
+MAX_WALL_TIME = float(os.getenv("GEMINI_MAX_WALL_TIME", "0"))
+if not MAX_WALL_TIME >= 0:
+    raise ValueError(f"GEMINI_MAX_WALL_TIME must be zero or more (got {MAX_WALL_TIME})")
+
+# Seconds to establish a connection, separate from each request's overall timeout
+CONNECT_TIMEOUT = float(os.getenv("GEMINI_CONNECT_TIMEOUT", "10"))
+if CONNECT_TIMEOUT <= 0:
+    raise ValueError(f"GEMINI_CONNECT_TIMEOUT must be positive (got {CONNECT_TIMEOUT})")
+
+# Address family for outbound connections: v4, v6, or auto
+IP_VERSION = os.getenv("GEMINI_IP_VERSION", "auto").strip().lower()
+_LOCAL_ADDRESSES = {"v4": "0.0.0.0", "v6": "::", "auto": None}
+if IP_VERSION not in _LOCAL_ADDRESSES:
+    raise ValueError(f"GEMINI_IP_VERSION must be one of v4, v6, auto (got {IP_VERSION!r})")
+
+# Extra attempts for redirect resolution after a transient network error
+REDIRECT_RETRIES = int(os.getenv("GEMINI_REDIRECT_RETRIES", "1"))
+if REDIRECT_RETRIES < 0:
+    raise ValueError(f"GEMINI_REDIRECT_RETRIES must be zero or more (got {REDIRECT_RETRIES})")
+
+# Redirect resolution and its on-disk cache
+RESOLVE_REDIRECTS = os.getenv("GEMINI_RESOLVE_REDIRECTS", "true").lower() not in ("0", "false", "no")
+# Zero days turns the on-disk cache off
+_redirect_cache_days = float(os.getenv("GEMINI_REDIRECT_CACHE_DAYS", "7"))
+if not _redirect_cache_days >= 0:
+    raise ValueError(f"GEMINI_REDIRECT_CACHE_DAYS must be zero or more (got {_redirect_cache_days})")
+REDIRECT_CACHE_TTL = _redirect_cache_days * 86400
+REDIRECT_CACHE_PATH = (
+    Path(os.getenv("XDG_CACHE_HOME") or Path.home() / ".cache") / "gemini-mcp" / "redirects.json"
+)
+
+# Strip ANSI escapes and control characters from model output and fetched titles
+SANITIZE_OUTPUT = os.getenv("GEMINI_SANITIZE_OUTPUT", "true").lower() not in ("0", "false", "no")
+
+# Rule placed before the follow-up footer; empty omits it, unset keeps each tool's default
+SEPARATOR = os.getenv("GEMINI_SEPARATOR")
+
+# Append a summary of how often grounding tools were invoked
+SHOW_TOOL_USAGE = os.getenv("GEMINI_SHOW_TOOL_USAGE", "").lower() in ("1", "true", "yes")
+
+# Retry once at a higher thinking level when an answer has neither text nor sources
+RETRY_EMPTY_ANSWERS = os.getenv("GEMINI_RETRY_EMPTY_ANSWERS", "").lower() in ("1", "true", "yes")
+
+# Retry once with no tools when the API rejects google_search/url_context
+RETRY_WITHOUT_TOOLS = os.getenv("GEMINI_RETRY_WITHOUT_TOOLS", "").lower() in ("1", "true", "yes")
+
+
+def _http_client(timeout: float, **kwargs) -> httpx.Client:
+    """Build an HTTP client bound to the configured address family and connect timeout."""
+    local_address = _LOCAL_ADDRESSES[IP_VERSION]
+    if local_address:
+        kwargs["transport"] = httpx.HTTPTransport(local_address=local_address)
+        kwargs["mounts"] = _proxy_mounts(local_address)
+    return httpx.Client(timeout=httpx.Timeout(timeout, connect=min(CONNECT_TIMEOUT, timeout)), **kwargs)
+
+
+def _proxy_mounts(local_address: str) -> dict:
+    """Route through HTTP(S)_PROXY/ALL_PROXY, which httpx ignores once a transport is given."""
+    proxies = urllib.request.getproxies()
+    mounts = {}
+    for scheme in ("http", "https"):
+        proxy = proxies.get(scheme) or proxies.get("all")
+        if proxy:
+            mounts[f"{scheme}://"] = httpx.HTTPTransport(proxy=proxy, local_address=local_address)
+    if not mounts:
+        return mounts
+
+    # NO_PROXY hosts (and their subdomains) fall back to the direct transport
+    for host in (proxies.get("no") or "").split(","):
+        host = host.strip().lstrip(".")
+        if host == "*":
+            return {}
+        if host:
+            mounts[f"all://*{host}"] = None
+    return mounts
 
 
 def _create_interaction(
     input_content: str | list,
-    thinking_level: Literal["minimal", "low", "medium", "high"] = "medium",
+    thinking_level: ThinkingLevel = "medium",
     previous_interaction_id: Optional[str] = None,
     max_tokens: int = 8192,
     system_instruction: Optional[str] = None,
+    google_search: bool = True,
+    url_context: bool = True,
+    store: bool = True,
+    temperature: Optional[float] = None,
+    top_p: Optional[float] = None,
+    citation_markers: bool = False,
 ) -> dict:
     """
     Create an interaction with the Gemini API.
 
     Returns parsed response with text, sources, interaction_id, and usage.
     """
+    if not 1 <= max_tokens <= MAX_OUTPUT_TOKENS:
+        return {
+            "error": f"max_tokens must be between 1 and {MAX_OUTPUT_TOKENS} (got {max_tokens})",
+            "interaction_id": None,
+            "status": "failed"
+        }
+    if temperature is not None and not 0.0 <= temperature <= 2.0:
+        return {
+            "error": f"temperature must be between 0.0 and 2.0 (got {temperature})",
+            "interaction_id": None,
+            "status": "failed"
+        }
+    if top_p is not None and not 0.0 <= top_p <= 1.0:
+        return {
+            "error": f"top_p must be between 0.0 and 1.0 (got {top_p})",
+            "interaction_id": None,
+            "status": "failed"
+        }
+
     payload = {
         "model": MODEL,
         "input": input_content,
-        "store": True,  # Enable caching
+        "store": store,  # Stored interactions can be continued via previous_interaction_id
         "generation_config": {
             "thinking_level": thinking_level,
             "max_output_tokens": max_tokens,
         },
-        # Always include grounding tools - model auto-decides when to use them
-        "tools": [
-            {"type": "google_search"},
-            {"type": "url_context"}
-        ]
+        # Enabled grounding tools - model auto-decides when to use them
+        "tools": []
     }
 
+    # Sampling controls are only sent when set, leaving the model defaults otherwise
+    if temperature is not None:
+        payload["generation_config"]["temperature"] = temperature
+    if top_p is not None:
+        payload["generation_config"]["top_p"] = top_p
+
+    if google_search:
+        payload["tools"].append({"type": "google_search"})
+    if url_context:
+        payload["tools"].append({"type": "url_context"})
+
     if previous_interaction_id:
         payload["previous_interaction_id"] = previous_interaction_id
 
     if system_instruction:
         payload["system_instruction"] = system_instruction
 
+    # Per-call id so client-side errors can be matched to server-side logs
+    request_id = str(uuid.uuid4())
     headers = {
         "x-goog-api-key": GEMINI_API_KEY,
-        "Content-Type": "application/json"
+        "Content-Type": "application/json",
+        "x-request-id": request_id,
     }
 
+    ungrounded = False
+    retried_level = None
+
     try:
-        with httpx.Client(timeout=120.0) as client:
-            response = client.post(INTERACTIONS_ENDPOINT, json=payload, headers=headers)
+        with _http_client(120.0) as client:
+            response = _post_with_retry(client, payload, headers)
+            if RETRY_WITHOUT_TOOLS and payload["tools"] and _is_tool_error(response):
+                print(
+                    f"Grounding tools rejected ({response.status_code}), retrying without tools",
+                    file=sys.stderr,
+                )
+                payload["tools"] = []
+                ungrounded = True
+                response = _post_with_retry(client, payload, headers)
             response.raise_for_status()
             data = response.json()
 
-        return _parse_interaction_response(data)
+            if RETRY_EMPTY_ANSWERS and _is_empty_answer(data):
+                retried_level = _next_thinking_level(thinking_level)
+                print(
+                    f"Empty answer with no sources, retrying once at thinking_level={retried_level}",
+                    file=sys.stderr,
+                )
+                payload["generation_config"]["thinking_level"] = retried_level
+                response = _post_with_retry(client, payload, headers)
+                response.raise_for_status()
+                data = response.json()
+
+        result = _parse_interaction_response(data, citation_markers)
+        result["stored"] = store
+        result["warnings"] = []
+        budget_warning = _token_budget_warning(thinking_level, max_tokens)
+        if budget_warning:
+            result["warnings"].append(budget_warning)
+        if ungrounded:
+            result["warnings"].append("Grounding tools were unavailable; this answer is not grounded.")
+        if retried_level:
+            result["warnings"].append(
+                f"The first answer was empty; this one was retried at thinking_level={retried_level}."
+            )
+        return result
 
     except httpx.HTTPStatusError as e:
+        ids = _describe_request_ids(request_id, e.response)
+        if previous_interaction_id and _is_expired_error(e.response):
+            return {
+                "error": (
+                    f"Interaction {previous_interaction_id} has expired or no longer exists. "
+                    "Start a fresh conversation by asking again without interaction_id. "
+                    f"({ids})"
+                ),
+                "interaction_id": None,
+                "status": "failed",
+                "expired": True,
+            }
         return {
-            "error": f"API error: {e.response.status_code} - {e.response.text}",
+            "error": f"API error: {_describe_api_error(e.response)} ({ids})",
             "interaction_id": None,
             "status": "failed"
         }
     except Exception as e:
         return {
-            "error": f"Request failed: {str(e)}",
+            "error": f"Request failed: {str(e)} (request id: {request_id})",
             "interaction_id": None,
             "status": "failed"
         }
 
 
-def _parse_interaction_response(data: dict) -> dict:
-    """Parse the interaction response into a structured format."""
+def _is_empty_answer(data: dict) -> bool:
+    """Check whether a response has no answer text and no sources to show for it."""
+    result = _parse_interaction_response(data)
+    return not result["text"].strip() and not result["sources"]
+
+
+def _next_thinking_level(thinking_level: ThinkingLevel) -> ThinkingLevel:
+    """Return the next higher thinking level, staying at high once there."""
+    levels = ["minimal", "low", "medium", "high"]
+    return levels[min(levels.index(thinking_level) + 1, len(levels) - 1)]
+
+
+def _token_budget_warning(thinking_level: ThinkingLevel, max_tokens: int) -> Optional[str]:
+    """Warn when max_tokens leaves too little room for an answer after high thinking."""
+    if thinking_level == "high" and max_tokens < HIGH_THINKING_MIN_TOKENS:
+        return (
+            f"max_tokens={max_tokens} is low for thinking_level=high; reasoning may use most of "
+            f"the budget and truncate the answer. Consider {HIGH_THINKING_MIN_TOKENS} or more."
+        )
+    return None
+
+
+def _delete_interaction(interaction_id: str) -> dict:
+    """Delete a stored interaction from the Gemini API."""
+    # Dot segments would be normalized away, so they can't name a single interaction
+    if not interaction_id.strip() or interaction_id in (".", ".."):
+        return {"error": "invalid interaction_id", "interaction_id": interaction_id, "status": "failed"}
+
+    headers = {"x-goog-api-key": GEMINI_API_KEY}
+
+    try:
+        with _http_client(30.0) as client:
+            # Escape "/", "?" and "#" so the id stays a single path segment
+            url = f"{INTERACTIONS_ENDPOINT}/{quote(interaction_id, safe='')}"
+            response = client.delete(url, headers=headers)
+            response.raise_for_status()
+        return {"interaction_id": interaction_id, "status": "deleted"}
+
+    except httpx.HTTPStatusError as e:
+        if e.response.status_code == 404:
+            error = "not found"
+        else:
+            error = f"API error: {_describe_api_error(e.response)}"
+        return {"error": error, "interaction_id": interaction_id, "status": "failed"}
+    except Exception as e:
+        return {
+            "error": f"Request failed: {str(e)}",
+            "interaction_id": interaction_id,
+            "status": "failed"
+        }
+
+
+# Failures before the request reached the server. Anything later (read timeouts, dropped
+# connections) may have created a stored, billed interaction, so it is never re-sent
+_RETRYABLE_TRANSPORT_ERRORS = (httpx.ConnectError, httpx.ConnectTimeout)
+
+
+def _post_with_retry(client: httpx.Client, payload: dict, headers: dict) -> httpx.Response:
+    """POST an interaction, retrying connection errors and 429/5xx with backoff."""
+    deadline = time.monotonic() + MAX_WALL_TIME if MAX_WALL_TIME else None
+    for attempt in range(MAX_RETRIES + 1):
+        response = None
+        try:
+            response = _post_interaction(client, payload, headers, deadline)
+        except _RETRYABLE_TRANSPORT_ERRORS as e:
+            if attempt == MAX_RETRIES:
+                raise
+            error = e
+            reason = str(e) or type(e).__name__
+        else:
+            if response.status_code not in RETRYABLE_STATUSES or attempt == MAX_RETRIES:
+                return response
+            reason = f"HTTP {response.status_code}"
+
+        delay = _retry_delay(attempt, response)
+        if deadline is not None and time.monotonic() + delay >= deadline:
+            print(f"Request failed ({reason}), no time left to retry within GEMINI_MAX_WALL_TIME", file=sys.stderr)
+            if response is None:
+                raise error
+            return response
+        print(
+            f"Request failed ({reason}), retrying in {delay:.1f}s "
+            f"(attempt {attempt + 2}/{MAX_RETRIES + 1})",
+            file=sys.stderr,
+        )
+        time.sleep(delay)
+
+
+def _post_interaction(
+    client: httpx.Client, payload: dict, headers: dict, deadline: Optional[float]
+) -> httpx.Response:
+    """POST once, reading the body under the wall-time deadline when one is set."""
+    if deadline is None:
+        return client.post(INTERACTIONS_ENDPOINT, json=payload, headers=headers)
+
+    # httpx timeouts bound each network operation, not the whole exchange, so also check between chunks
+    remaining = max(deadline - time.monotonic(), 0.001)
+    timeout = httpx.Timeout(
+        min(client.timeout.read or remaining, remaining),
+        connect=min(client.timeout.connect or remaining, remaining),
+    )
+    request = client.build_request("POST", INTERACTIONS_ENDPOINT, json=payload, headers=headers, timeout=timeout)
+    response = client.send(request, stream=True)
+    response.stream = _DeadlineStream(response.stream, deadline, request)
+    try:
+        response.read()
+    finally:
+        response.close()
+    return response
+
+
+class _DeadlineStream(httpx.SyncByteStream):
+    """Response body stream that aborts once the wall-time deadline has passed."""
+
+    def __init__(self, stream, deadline: float, request: httpx.Request):
+        self._stream = stream
+        self._deadline = deadline
+        self._request = request
+
+    def __iter__(self):
+        for chunk in self._stream:
+            if time.monotonic() > self._deadline:
+                raise httpx.ReadTimeout(
+                    f"exceeded GEMINI_MAX_WALL_TIME of {MAX_WALL_TIME:g}s", request=self._request
+                )
+            yield chunk
+
+    def close(self) -> None:
+        self._stream.close()
+
+
+def _retry_delay(attempt: int, response: Optional[httpx.Response]) -> float:
+    """Exponential backoff with jitter, deferring to Retry-After when the server sends it."""
+    if response is not None:
+        try:
+            retry_after = float(response.headers.get("retry-after", ""))
+        except ValueError:
+            retry_after = math.nan
+        if math.isfinite(retry_after):
+            return min(max(retry_after, 0.0), 60.0)
+    return min(2 ** attempt, 30) + random.uniform(0, 1)
+
+
+def _error_envelope(response: httpx.Response) -> dict:
+    """Return the {"error": {...}} object from a Google API error body, or {} if absent."""
+    try:
+        error = response.json().get("error")
+    except Exception:
+        return {}
+    return error if isinstance(error, dict) else {}
+
+
+def _describe_api_error(response: httpx.Response) -> str:
+    """Summarize an error response, unpacking Google's {"error": {...}} envelope."""
+    error = _error_envelope(response)
+
+    if error.get("message"):
+        code = error.get("code", response.status_code)
+        status = error.get("status")
+        detail = f"{code} {status}" if status else f"{code}"
+        detail += f" - {error['message']}"
+    else:
+        detail = f"{response.status_code} - {response.text}"
+
+    if response.status_code in (401, 403):
+        detail += " (check that GEMINI_API_KEY is set to a valid key)"
+    return detail
+
+
+def _describe_request_ids(request_id: str, response: httpx.Response) -> str:
+    """Describe the client request id, plus the server's own id when it returns one."""
+    description = f"request id: {request_id}"
+    server_id = response.headers.get("x-request-id")
+    if server_id and server_id != request_id:
+        description += f", server request id: {server_id}"
+    return description
+
+
+def _error_fields(error: dict) -> list[str]:
+    """Collect the request fields named by an error envelope's BadRequest details."""
+    fields = []
+    for detail in error.get("details") or []:
+        if not isinstance(detail, dict):
+            continue
+        for violation in detail.get("fieldViolations") or []:
+            if isinstance(violation, dict) and isinstance(violation.get("field"), str):
+                fields.append(violation["field"])
+    return fields
+
+
+def _error_reasons(error: dict) -> list[str]:
+    """Collect the ErrorInfo reasons (e.g. API_KEY_INVALID) from an error envelope's details."""
+    return [
+        detail["reason"] for detail in error.get("details") or []
+        if isinstance(detail, dict) and isinstance(detail.get("reason"), str)
+    ]
+
+
+def _is_tool_error(response: httpx.Response) -> bool:
+    """Check whether a failed request was rejected because of the grounding tools."""
+    if response.status_code not in (400, 403):
+        return False
+    error = _error_envelope(response)
+    # A field violation on tools[...] is definitive; otherwise the message must name a tool type
+    if any(re.match(r"tools\b", field) for field in _error_fields(error)):
+        return True
+    message = str(error.get("message") or "").lower()
+    return any(tool in message for tool in ("google_search", "url_context"))
+
+
+def _is_expired_error(response: httpx.Response) -> bool:
+    """Check whether a follow-up failed because its previous interaction is gone."""
+    if response.status_code not in (400, 404):
+        return False
+    error = _error_envelope(response)
+    # An expired or invalid API key must surface as itself, not as a lost conversation
+    if any(reason.startswith("API_KEY") for reason in _error_reasons(error)):
+        return False
+    if any(field.startswith("previous_interaction_id") for field in _error_fields(error)):
+        return True
+    # A bad model or endpoint must not read as expiry, so the message has to name the interaction
+    message = str(error.get("message") or "").lower()
+    if "previous_interaction_id" in message:
+        return True
+    if re.search(r"\binteraction\b", message) is None:
+        return False
+    return response.status_code == 404 or "expired" in message
+
+
+def _parse_interaction_response(data: dict, citation_markers: bool = False) -> dict:
+    """
+    Parse the interaction response into a structured format.
+
+    With citation_markers, [n] markers matching the sources list are inserted into the text.
+    """
     result = {
         "interaction_id": data.get("id"),
         "status": data.get("status"),
         "text": "",
         "sources": [],
-        "usage": data.get("usage", {})
+        "usage": data.get("usage", {}),
+        "tool_usage": {"google_search": 0, "url_context": 0},
     }
 
     for output in data.get("outputs", []):
         output_type = output.get("type")
 
         if output_type == "text":
-            result["text"] += output.get("text", "")
-            # Extract annotations as inline citations
+            # Extract annotations as inline citations numbered to match the sources list
+            markers = []
             for ann in output.get("annotations", []):
                 source = ann.get("source")
-                if source and source not in result["sources"]:
-                    result["sources"].append(source)
+                if not source:
+                    continue
+                number = _add_source(result["sources"], source)
+                end_index = ann.get("end_index")
+                if isinstance(end_index, int):
+                    markers.append((end_index, number))
+            text = output.get("text") or ""
+            result["text"] += _insert_citation_markers(text, markers) if citation_markers else text
+
+        elif output_type == "google_search_call":
+            queries = (output.get("arguments") or {}).get("queries") or []
+            result["tool_usage"]["google_search"] += len(queries) or 1
+
+        elif output_type == "url_context_call":
+            urls = (output.get("arguments") or {}).get("urls") or []
+            result["tool_usage"]["url_context"] += len(urls) or 1
 
         elif output_type == "google_search_result":
             for item in output.get("result", []):
@@ -131,8 +604,8 @@ def _parse_interaction_response(data: dict) -> dict:
                     "url": item.get("url"),
                     "title": item.get("title")
                 }
-                if source["url"] and source not in result["sources"]:
-                    result["sources"].append(source)
+                if source["url"]:
+                    _add_source(result["sources"], source)
 
         elif output_type == "url_context_result":
             for item in output.get("result", []):
@@ -141,31 +614,180 @@ def _parse_interaction_response(data: dict) -> dict:
                         "url": item.get("url"),
                         "title": "URL Context"
                     }
-                    if source["url"] and source not in result["sources"]:
-                        result["sources"].append(source)
+                    if source["url"]:
+                        _add_source(result["sources"], source)
 
     return result
 
 
-def _resolve_redirect_url(url: str) -> str:
-    """Resolve Google's grounding redirect URLs to actual source URLs."""
+def _add_source(sources: list, source: str | dict) -> int:
+    """Add a source unless its URL is already listed, returning its 1-based number."""
+    url = source.get("url") if isinstance(source, dict) else source
+    for number, existing in enumerate(sources, 1):
+        existing_url = existing.get("url") if isinstance(existing, dict) else existing
+        if existing_url == url:
+            # A titled search result replaces a bare annotation URL in place, keeping its number
+            if isinstance(source, dict) and not isinstance(existing, dict):
+                sources[number - 1] = source
+            return number
+    sources.append(source)
+    return len(sources)
+
+
+def _insert_citation_markers(text: str, markers: list[tuple[int, int]]) -> str:
+    """Insert [n] markers at annotation end offsets, which the API gives in UTF-8 bytes."""
+    if not markers:
+        return text
+
+    encoded = text.encode("utf-8")
+    numbers_by_end: dict[int, set[int]] = {}
+    for end_index, number in markers:
+        if 0 <= end_index <= len(encoded):
+            # Never split a multi-byte character
+            while end_index < len(encoded) and encoded[end_index] & 0xC0 == 0x80:
+                end_index += 1
+            numbers_by_end.setdefault(end_index, set()).add(number)
+
+    # Insert from the end so earlier offsets stay valid
+    for end_index in sorted(numbers_by_end, reverse=True):
+        label = "".join(f"[{n}]" for n in sorted(numbers_by_end[end_index]))
+        encoded = encoded[:end_index] + label.encode("utf-8") + encoded[end_index:]
+    return encoded.decode("utf-8")
+
+
+_redirect_cache: Optional[dict] = None
+_redirect_cache_lock = threading.Lock()
+
+
+def _load_redirect_cache() -> dict:
+    """Load unexpired entries from the on-disk redirect cache once per process."""
+    global _redirect_cache
+    if _redirect_cache is None:
+        try:
+            entries = json.loads(REDIRECT_CACHE_PATH.read_text())
+        except (OSError, ValueError):
+            entries = {}
+        # A malformed file counts as empty and is overwritten by the next store
+        if not isinstance(entries, dict):
+            entries = {}
+        now = time.time()
+        _redirect_cache = {
+            url: entry for url, entry in entries.items()
+            if _is_valid_redirect_entry(entry) and now - entry["resolved_at"] < REDIRECT_CACHE_TTL
+        }
+    return _redirect_cache
+
+
+def _is_valid_redirect_entry(entry) -> bool:
+    """Check that a cache entry has a string url and a numeric resolved_at."""
+    if not isinstance(entry, dict) or not isinstance(entry.get("url"), str):
+        return False
+    resolved_at = entry.get("resolved_at")
+    if isinstance(resolved_at, bool) or not isinstance(resolved_at, (int, float)):
+        return False
+    return math.isfinite(resolved_at)
+
+
+def _cached_redirect(url: str) -> Optional[str]:
+    """Return a previously resolved target for a redirect URL, if still fresh."""
+    if not REDIRECT_CACHE_TTL:
+        return None
+    with _redirect_cache_lock:
+        entry = _load_redirect_cache().get(url)
+    if entry and time.time() - entry["resolved_at"] < REDIRECT_CACHE_TTL:
+        return entry["url"]
+    return None
+
+
+def _store_redirect(url: str, resolved: str) -> None:
+    """Remember a resolved redirect in memory and on disk."""
+    if not REDIRECT_CACHE_TTL:
+        return
+    with _redirect_cache_lock:
+        cache = _load_redirect_cache()
+        cache[url] = {"url": resolved, "resolved_at": time.time()}
+        try:
+            REDIRECT_CACHE_PATH.parent.mkdir(parents=True, exist_ok=True)
+            # A unique temp file per write, so concurrent servers never share a partial file
+            with tempfile.NamedTemporaryFile(
+                "w", dir=REDIRECT_CACHE_PATH.parent, prefix=".redirects-", suffix=".tmp", delete=False
+            ) as tmp_file:
+                json.dump(cache, tmp_file)
+            try:
+                os.replace(tmp_file.name, REDIRECT_CACHE_PATH)
+            except OSError:
+                os.unlink(tmp_file.name)
+                raise
+        except OSError:
+            pass
+
+
+def _resolve_redirect_url(url: str, cache: bool = True) -> str:
+    """
+    Resolve Google's grounding redirect URLs to actual source URLs.
+
+    With cache=False (unstored interactions) the result is neither read from nor written to the cache.
+    """
     if not url or "vertexaisearch.cloud.google.com/grounding-api-redirect" not in url:
         return url
+    if not RESOLVE_REDIRECTS:
+        return url
+    if not cache:
+        return _fetch_redirect_location(url)
 
-    try:
-        with httpx.Client(timeout=5.0, follow_redirects=False) as client:
-            response = client.head(url)
-            if response.status_code in (301, 302, 303, 307, 308):
-                return response.headers.get("location", url)
-    except Exception:
-        pass
+    cached = _cached_redirect(url)
+    if cached:
+        return cached
+
+    resolved = _fetch_redirect_location(url)
+    if resolved != url:
+        _store_redirect(url, resolved)
+    return resolved
+
+
+def _fetch_redirect_location(url: str) -> str:
+    """Look up where a redirect URL points, returning the URL itself on failure."""
+    # All attempts share one time budget so retries can't stall formatting
+    deadline = time.monotonic() + 5.0
+    for attempt in range(REDIRECT_RETRIES + 1):
+        remaining = deadline - time.monotonic()
+        if remaining <= 0:
+            break
+        try:
+            with _http_client(remaining, follow_redirects=False) as client:
+                response = client.head(url)
+                location = _redirect_location(response)
+                if location:
+                    return location
+
+                # Some servers reject HEAD (405) or omit location on it; read GET headers only
+                with client.stream("GET", url) as response:
+                    location = _redirect_location(response)
+                    if location:
+                        return location
+            break
+        except httpx.TransportError:
+            if attempt < REDIRECT_RETRIES:
+                time.sleep(0.25)
+        except Exception:
+            break
     return url
 
 
-def _resolve_all_urls(sources: list) -> list:
+def _redirect_location(response: httpx.Response) -> Optional[str]:
+    """Return the redirect target of a response, if it has one."""
+    if response.status_code in (301, 302, 303, 307, 308):
+        return response.headers.get("location")
+    return None
+
+
+def _resolve_all_urls(sources: list, cache: bool = True) -> list:
     """Resolve all redirect URLs in parallel."""
     from concurrent.futures import ThreadPoolExecutor, as_completed
 
+    if not RESOLVE_REDIRECTS:
+        return sources
+
     # Extract URLs that need resolving
     urls_to_resolve = []
     for source in sources:
@@ -182,7 +804,7 @@ def _resolve_all_urls(sources: list) -> list:
     # Resolve all in parallel
     url_map = {}
     with ThreadPoolExecutor(max_workers=10) as executor:
-        futures = {executor.submit(_resolve_redirect_url, url): url for url in urls_to_resolve}
+        futures = {executor.submit(_resolve_redirect_url, url, cache): url for url in urls_to_resolve}
         for future in as_completed(futures):
             original_url = futures[future]
             try:
@@ -205,18 +827,21 @@ def _resolve_all_urls(sources: list) -> list:
     return resolved
 
 
-def _resolve_text_urls(text: str) -> str:
+def _resolve_text_urls(text: str, cache: bool = True) -> str:
     """Resolve redirect URLs embedded in the response text."""
     from concurrent.futures import ThreadPoolExecutor, as_completed
 
-    redirect_pattern = r'https://vertexaisearch\.cloud\.google\.com/grounding-api-redirect/[^\s\)\]\"\'<>]+'
+    if not RESOLVE_REDIRECTS:
+        return text
+
+    redirect_pattern = r'https://vertexaisearch\.cloud\.google\.com/grounding-api-redirect/[^\s\)\[\]\"\'<>]+'
     urls = list(set(re.findall(redirect_pattern, text)))
     if not urls:
         return text
 
     url_map = {}
     with ThreadPoolExecutor(max_workers=10) as executor:
-        futures = {executor.submit(_resolve_redirect_url, url): url for url in urls}
+        futures = {executor.submit(_resolve_redirect_url, url, cache): url for url in urls}
         for future in as_completed(futures):
             original = futures[future]
             try:
@@ -229,17 +854,29 @@ def _resolve_text_urls(text: str) -> str:
     return text
 
 
-def _format_response(result: dict) -> str:
-    """Format the parsed result into a readable string."""
+def _format_response(result: dict, separator: Optional[str] = "---") -> str:
+    """
+    Format the parsed result into a readable string.
+
+    The separator is the rule placed before the follow-up footer; pass None to omit it.
+    """
     if "error" in result:
         return f"Error: {result['error']}"
 
-    output = [_resolve_text_urls(result.get("text", ""))]
+    # Redirects from unstored interactions stay out of the on-disk cache too
+    stored = result.get("stored", True)
+
+    text = result.get("text", "")
+    if text.strip():
+        output = [_resolve_text_urls(text, cache=stored)]
+    else:
+        # Tool-only turns can come back with no text at all
+        output = ["(no text output — tool results only)"]
 
     # Add sources (resolve redirect URLs in parallel)
     sources = result.get("sources", [])
     if sources:
-        resolved_sources = _resolve_all_urls(sources)
+        resolved_sources = _resolve_all_urls(sources, cache=stored)
         output.append("\n\nSources:")
         for i, source in enumerate(resolved_sources, 1):
             if isinstance(source, dict):
@@ -249,19 +886,94 @@ def _format_response(result: dict) -> str:
             else:
                 output.append(f"{i}. {source}")
 
-    # Add follow-up instructions
-    output.append("\n---")
-    output.append(f"To follow up, use interaction_id: {result.get('interaction_id', 'N/A')}")
+    usage_line = _format_usage(result.get("usage") or {})
+    if usage_line:
+        output.append(f"\n{usage_line}")
+
+    if SHOW_TOOL_USAGE:
+        usage = result.get("tool_usage", {})
+        output.append(
+            f"\nTool usage: google_search queries: {usage.get('google_search', 0)}, "
+            f"url_context fetches: {usage.get('url_context', 0)}"
+        )
+
+    for warning in result.get("warnings", []):
+        output.append(f"\nNote: {warning}")
+
+    # Add follow-up instructions (unstored interactions can't be continued)
+    if stored:
+        if separator:
+            output.append(f"\n{separator}")
+        output.append(f"To follow up, use interaction_id: {result.get('interaction_id', 'N/A')}")
 
     return "\n".join(output)
 
 
+def _format_usage(usage: dict) -> Optional[str]:
+    """Summarize token usage, with an estimated cost when SHOW_COST is set."""
+    input_tokens = usage.get("total_input_tokens") or 0
+    output_tokens = usage.get("total_output_tokens") or 0
+    thought_tokens = usage.get("total_thought_tokens") or 0
+    if not (input_tokens or output_tokens or thought_tokens):
+        return None
+
+    line = f"Tokens: {input_tokens} in / {output_tokens} out / {thought_tokens} reasoning"
+    if SHOW_COST:
+        pricing = MODEL_PRICING.get(MODEL)
+        if pricing and None not in pricing:
+            input_price, output_price = pricing
+            # Grounding results fed back to the model are billed as input
+            tool_tokens = usage.get("total_tool_use_tokens") or 0
+            billed_input = input_tokens + tool_tokens
+            cost = (billed_input * input_price + (output_tokens + thought_tokens) * output_price) / 1_000_000
+            line += f" (~${cost:.4f})"
+        else:
+            line += f" (no pricing for {MODEL})"
+    return line
+
+
+def _format_response_safe(result: dict, separator: Optional[str] = "---") -> str:
+    """Format the result, falling back to the raw answer if post-processing fails."""
+    try:
+        formatted = _format_response(result, separator)
+    except Exception as e:
+        print(f"Formatting failed, returning raw answer: {e}", file=sys.stderr)
+        formatted = result.get("text", "")
+        if result.get("stored", True):
+            rule = f"{separator}\n" if separator else ""
+            formatted += f"\n\n{rule}To follow up, use interaction_id: {result.get('interaction_id', 'N/A')}"
+
+    return _sanitize_output(formatted) if SANITIZE_OUTPUT else formatted
+
+
+def _footer_separator(default: str) -> Optional[str]:
+    """Return GEMINI_SEPARATOR if set (None when empty), else the tool's default rule."""
+    if SEPARATOR is None:
+        return default
+    return SEPARATOR or None
+
+
+# OSC sequences need their BEL/ST terminator; a stray "ESC ]" only loses those two bytes
+_ANSI_ESCAPE_PATTERN = re.compile(r'\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b\n]*(?:\x07|\x1b\\)|\x1b[@-_]')
+_CONTROL_CHAR_PATTERN = re.compile(r'[\x00-\x08\x0b-\x1f\x7f-\x9f]')
+
+
+def _sanitize_output(text: str) -> str:
+    """Strip ANSI escapes and control characters, keeping newlines and tabs."""
+    text = text.replace("\r\n", "\n")
+    text = _ANSI_ESCAPE_PATTERN.sub("", text)
+    return _CONTROL_CHAR_PATTERN.sub("", text)
+
+
 # MCP Tools
 
 @mcp.tool(annotations=_TOOL_ANNOTATIONS)
 def search(
     query: str,
     max_results: int = 10,
+    thinking_level: ThinkingLevel = "minimal",
+    max_tokens: int = 4096,
+    system_instruction: Optional[str] = None,
 ) -> str:
     """
     Quick web search with minimal thinking. Returns structured results.
@@ -271,11 +983,14 @@ def search(
     Args:
         query: Search query
         max_results: Maximum number of results to return (default: 10)
+        thinking_level: Reasoning depth: minimal, low, medium, or high (default: minimal)
+        max_tokens: Maximum response length (default: 4096)
+        system_instruction: Replaces the built-in result-format instruction
 
     Returns:
         Structured search results with titles, URLs, and snippets
     """
-    system_instruction = f"""Search for the query and return results in this exact format:
+    default_instruction = f"""Search for the query and return results in this exact format:
 
 ---
 TITLE: [page title]
@@ -285,14 +1000,16 @@ SNIPPET: [2-3 sentence excerpt]
 
 Return up to {max_results} results. No additional commentary or analysis."""
 
+    # No citation markers here: they would land inside the exact TITLE/URL/SNIPPET format
     result = _create_interaction(
         input_content=query,
-        thinking_level="minimal",
-        system_instruction=system_instruction,
-        max_tokens=4096,
+        thinking_level=thinking_level,
+        system_instruction=system_instruction or default_instruction,
+        max_tokens=max_tokens,
     )
 
-    return _format_response(result)
+    # Results are delimited by ---, so use a distinct rule before the footer
+    return _format_response_safe(result, separator=_footer_separator("==="))
 
 
 @mcp.tool(annotations=_TOOL_ANNOTATIONS)
@@ -300,6 +1017,14 @@ def ask(
     query: str,
     interaction_id: Optional[str] = None,
     max_tokens: int = 8192,
+    thinking_level: ThinkingLevel = "high",
+    google_search: bool = True,
+    url_context: bool = True,
+    store: bool = True,
+    temperature: Optional[float] = None,
+    top_p: Optional[float] = None,
+    system_instruction: Optional[str] = None,
+    restart_if_expired: bool = False,
 ) -> str:
     """
     Get grounded answers with balanced reasoning.
@@ -311,20 +1036,75 @@ def ask(
         query: Your question
         interaction_id: Pass the interaction_id from a previous response to continue that conversation
         max_tokens: Maximum response length (default: 8192)
+        thinking_level: Reasoning depth: minimal, low, medium, or high (default: high)
+        google_search: Let the model search the web (default: True)
+        url_context: Let the model read linked pages (default: True)
+        store: Keep the interaction so it can be followed up; set False for sensitive
+            queries that shouldn't be retained (default: True)
+        temperature: Sampling temperature from 0.0 (deterministic) to 2.0 (default: model's own)
+        top_p: Nucleus sampling cutoff from 0.0 to 1.0 (default: model's own)
+        system_instruction: Replaces the built-in instruction, e.g. to change persona or format
+        restart_if_expired: If interaction_id has expired, ask again as a new conversation
+            instead of failing; the answer then lacks the earlier context (default: False)
 
     Returns:
-        Answer with sources. Use the returned interaction_id to ask follow-up questions.
+        Answer with sources. Use the returned interaction_id to ask follow-up questions
+        (not returned when store is False).
     """
-    result = _create_interaction(
+    default_instruction = "Be concise and factual. Cite sources when using web information."
+
+    options = dict(
         input_content=query,
-        thinking_level="high",
-        previous_interaction_id=interaction_id,
+        thinking_level=thinking_level,
         max_tokens=max_tokens,
-        system_instruction="Be concise and factual. Cite sources when using web information.",
+        system_instruction=system_instruction or default_instruction,
+        google_search=google_search,
+        url_context=url_context,
+        store=store,
+        temperature=temperature,
+        top_p=top_p,
+        citation_markers=True,
     )
+    result = _create_interaction(previous_interaction_id=interaction_id, **options)
+
+    if restart_if_expired and result.get("expired"):
+        result = _create_interaction(**options)
+        if "error" not in result:
+            result.setdefault("warnings", []).append(
+                f"Interaction {interaction_id} had expired; this answer started a new "
+                "conversation without its earlier context."
+            )
+
+    return _format_response_safe(result, separator=_footer_separator("---"))
+
 
-    return _format_response(result)
+@mcp.tool(annotations=_DESTRUCTIVE_TOOL_ANNOTATIONS)
+def delete_interactions(interaction_ids: list[str]) -> str:
+    """
+    Permanently delete stored interactions.
+
+    Deleted interactions can no longer be continued with interaction_id.
+
+    Args:
+        interaction_ids: One or more interaction_ids to delete
+
+    Returns:
+        One line per interaction_id reporting whether it was deleted
+    """
+    if not interaction_ids:
+        return "Error: interaction_ids must list at least one interaction_id"
+
+    lines = []
+    for interaction_id in interaction_ids:
+        result = _delete_interaction(interaction_id)
+        if "error" in result:
+            lines.append(f"{interaction_id}: {result['error']}")
+        else:
+            lines.append(f"{interaction_id}: deleted")
 
+    # Error lines echo API response bodies
+    output = "\n".join(lines)
+    return _sanitize_output(output) if SANITIZE_OUTPUT else output
 
 
 if __name__ == "__main__":
@@ -0,0 +1,38 @@
+"""Test setup: import server.py without a real API key or FastMCP runtime."""
+
+import os
+import sys
+import types
+from pathlib import Path
+
+import pytest
+
+os.environ.setdefault("GEMINI_API_KEY", "test-key")
+sys.path.insert(0, str(Path(__file__).resolve().parent.parent))
+
+
+class _StubFastMCP:
+    """Stand-in for FastMCP whose tool decorator returns the function unchanged."""
+
+    def __init__(self, *args, **kwargs):
+        pass
+
+    def tool(self, *args, **kwargs):
+        return lambda fn: fn
+
+    def run(self):
+        pass
+
+
+sys.modules["fastmcp"] = types.SimpleNamespace(FastMCP=_StubFastMCP)
+
+import server  # noqa: E402
+
+
+@pytest.fixture(autouse=True)
+def isolated_redirect_cache(tmp_path, monkeypatch):
+    """Point the redirect cache at a temp file so tests never touch ~/.cache."""
+    path = tmp_path / "redirects.json"
+    monkeypatch.setattr(server, "REDIRECT_CACHE_PATH", path)
+    monkeypatch.setattr(server, "_redirect_cache", None)
+    return path
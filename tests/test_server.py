@@ -0,0 +1,792 @@
+import importlib.util
+import json
+
+import httpx
+import pytest
+
+import server
+
+
+def _error_response(status_code: int, error=None, text: str = "") -> httpx.Response:
+    if error is not None:
+        return httpx.Response(status_code, json={"error": error})
+    return httpx.Response(status_code, text=text)
+
+
+def _reload_server():
+    """Execute server.py afresh so module-level config picks up patched env vars."""
+    spec = importlib.util.spec_from_file_location("server_reloaded", server.__file__)
+    module = importlib.util.module_from_spec(spec)
+    spec.loader.exec_module(module)
+    return module
+
+
+def _mock_api(monkeypatch, handler):
+    monkeypatch.setattr(
+        server, "_http_client",
+        lambda timeout, **kwargs: httpx.Client(transport=httpx.MockTransport(handler), **kwargs),
+    )
+
+
+@pytest.mark.parametrize("status_code, error", [
+    (400, {"code": 400, "message": "Tool google_search is not supported for this model"}),
+    (403, {"code": 403, "message": "url_context is not enabled for this project"}),
+    (400, {
+        "code": 400,
+        "message": "Invalid value",
+        "details": [{
+            "@type": "type.googleapis.com/google.rpc.BadRequest",
+            "fieldViolations": [{"field": "tools[1].type", "description": "unknown tool"}],
+        }],
+    }),
+])
+def test_is_tool_error_matches_tool_rejections(status_code, error):
+    assert server._is_tool_error(_error_response(status_code, error))
+
+
+@pytest.mark.parametrize("response", [
+    # Mentions of "tool" outside the tools field are not tool rejections
+    _error_response(400, {"code": 400, "message": "Prompt about power tools was blocked"}),
+    _error_response(400, text="tool google_search url_context"),
+    _error_response(403, {"code": 403, "message": "API key not valid"}),
+    _error_response(404, {"code": 404, "message": "google_search not found"}),
+    _error_response(400, {
+        "code": 400,
+        "message": "Invalid value",
+        "details": [{"fieldViolations": [{"field": "generation_config.tool_budget"}]}],
+    }),
+])
+def test_is_tool_error_ignores_other_errors(response):
+    assert not server._is_tool_error(response)
+
+
+@pytest.mark.parametrize("status_code, error", [
+    (404, {"code": 404, "message": "Interaction abc123 was not found", "status": "NOT_FOUND"}),
+    (400, {"code": 400, "message": "Interaction has expired", "status": "INVALID_ARGUMENT"}),
+    (400, {
+        "code": 400,
+        "message": "Invalid argument",
+        "details": [{"fieldViolations": [{"field": "previous_interaction_id"}]}],
+    }),
+])
+def test_is_expired_error_matches_missing_interactions(status_code, error):
+    assert server._is_expired_error(_error_response(status_code, error))
+
+
+@pytest.mark.parametrize("response", [
+    _error_response(404, {
+        "code": 404,
+        "message": "models/gemini-nope is not found for API version v1beta",
+        "status": "NOT_FOUND",
+    }),
+    _error_response(404, text="<html>404 Not Found</html>"),
+    _error_response(400, {"code": 400, "message": "max_output_tokens is out of range"}),
+    _error_response(500, {"code": 500, "message": "Interaction has expired"}),
+    _error_response(400, {
+        "code": 400,
+        "message": "API key expired. Please renew the API key.",
+        "status": "INVALID_ARGUMENT",
+        "details": [{
+            "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+            "reason": "API_KEY_INVALID",
+            "domain": "googleapis.com",
+        }],
+    }),
+    _error_response(400, {"code": 400, "message": "API key expired. Please renew the API key."}),
+])
+def test_is_expired_error_ignores_other_errors(response):
+    assert not server._is_expired_error(response)
+
+
+def test_ask_restarts_expired_interaction(monkeypatch):
+    calls = []
+
+    def fake_create_interaction(previous_interaction_id=None, **options):
+        calls.append(previous_interaction_id)
+        if previous_interaction_id:
+            return {"error": "expired", "interaction_id": None, "status": "failed", "expired": True}
+        return {"interaction_id": "new-id", "text": "Fresh answer", "sources": [], "stored": True}
+
+    monkeypatch.setattr(server, "_create_interaction", fake_create_interaction)
+
+    output = server.ask("question", interaction_id="old-id", restart_if_expired=True)
+
+    assert calls == ["old-id", None]
+    assert "Fresh answer" in output
+    assert "Note: Interaction old-id had expired" in output
+    assert "interaction_id: new-id" in output
+
+
+def test_forced_ip_version_keeps_proxy_env(monkeypatch):
+    for name in ("http_proxy", "https_proxy", "all_proxy", "no_proxy"):
+        monkeypatch.delenv(name, raising=False)
+        monkeypatch.delenv(name.upper(), raising=False)
+    monkeypatch.setenv("https_proxy", "http://proxy.internal:3128")
+    monkeypatch.setenv("no_proxy", "localhost,.example.com")
+
+    mounts = server._proxy_mounts("0.0.0.0")
+
+    assert set(mounts) == {"https://", "all://*localhost", "all://*example.com"}
+    assert isinstance(mounts["https://"], httpx.HTTPTransport)
+    assert mounts["all://*example.com"] is None
+
+
+def test_forced_ip_version_without_proxy_env(monkeypatch):
+    for name in ("http_proxy", "https_proxy", "all_proxy", "no_proxy"):
+        monkeypatch.delenv(name, raising=False)
+        monkeypatch.delenv(name.upper(), raising=False)
+    monkeypatch.setenv("no_proxy", "example.com")
+
+    assert server._proxy_mounts("::") == {}
+
+
+def test_describe_api_error_unpacks_envelope():
+    response = _error_response(400, {
+        "code": 400,
+        "message": "max_output_tokens must be positive",
+        "status": "INVALID_ARGUMENT",
+    })
+
+    assert server._describe_api_error(response) == "400 INVALID_ARGUMENT - max_output_tokens must be positive"
+
+
+def test_describe_api_error_without_status():
+    response = _error_response(429, {"code": 429, "message": "Quota exceeded"})
+
+    assert server._describe_api_error(response) == "429 - Quota exceeded"
+
+
+@pytest.mark.parametrize("response", [
+    _error_response(502, text="Bad Gateway"),
+    httpx.Response(502, json={"error": "upstream down"}),
+    httpx.Response(502, json=["not", "an", "envelope"]),
+])
+def test_describe_api_error_falls_back_to_raw_body(response):
+    assert server._describe_api_error(response) == f"502 - {response.text}"
+
+
+@pytest.mark.parametrize("status_code", [401, 403])
+def test_describe_api_error_hints_at_api_key(status_code):
+    response = _error_response(status_code, {"code": status_code, "message": "API key not valid"})
+
+    assert server._describe_api_error(response).endswith("(check that GEMINI_API_KEY is set to a valid key)")
+
+
+@pytest.mark.parametrize("retry_after, expected", [
+    ("5", 5.0),
+    ("0", 0.0),
+    ("-5", 0.0),
+    ("600", 60.0),
+])
+def test_retry_delay_honours_retry_after(retry_after, expected):
+    response = httpx.Response(429, headers={"retry-after": retry_after})
+
+    assert server._retry_delay(0, response) == expected
+
+
+@pytest.mark.parametrize("retry_after", ["nan", "inf", "-inf", "Wed, 21 Oct 2015 07:28:00 GMT", ""])
+def test_retry_delay_falls_back_to_backoff(retry_after):
+    response = httpx.Response(503, headers={"retry-after": retry_after})
+
+    delay = server._retry_delay(2, response)
+
+    assert 4.0 <= delay <= 5.0
+
+
+def test_retry_delay_caps_backoff():
+    assert 30.0 <= server._retry_delay(10, None) <= 31.0
+
+
+@pytest.mark.parametrize("error", [httpx.ReadTimeout, httpx.RemoteProtocolError])
+def test_post_with_retry_skips_errors_after_sending(monkeypatch, error):
+    attempts = []
+
+    def handler(request):
+        attempts.append(request)
+        raise error("failed after the body was sent", request=request)
+
+    monkeypatch.setattr(server, "MAX_RETRIES", 3)
+    with httpx.Client(transport=httpx.MockTransport(handler)) as client:
+        with pytest.raises(error):
+            server._post_with_retry(client, {}, {})
+
+    assert len(attempts) == 1
+
+
+def test_post_with_retry_retries_connect_errors(monkeypatch):
+    attempts = []
+
+    def handler(request):
+        attempts.append(request)
+        if len(attempts) == 1:
+            raise httpx.ConnectError("connection refused", request=request)
+        return httpx.Response(200, json={})
+
+    monkeypatch.setattr(server, "MAX_RETRIES", 3)
+    monkeypatch.setattr(server.time, "sleep", lambda seconds: None)
+    with httpx.Client(transport=httpx.MockTransport(handler)) as client:
+        response = server._post_with_retry(client, {}, {})
+
+    assert response.status_code == 200
+    assert len(attempts) == 2
+
+
+def _failing_format(result, separator):
+    raise RuntimeError("boom")
+
+
+@pytest.mark.parametrize("separator, expected", [
+    ("---", "Answer\n\n---\nTo follow up, use interaction_id: abc"),
+    (None, "Answer\n\nTo follow up, use interaction_id: abc"),
+])
+def test_format_response_safe_fallback_separator(monkeypatch, separator, expected):
+    monkeypatch.setattr(server, "_format_response", _failing_format)
+    result = {"text": "Answer", "interaction_id": "abc", "stored": True}
+
+    assert server._format_response_safe(result, separator) == expected
+
+
+@pytest.mark.parametrize("configured, expected", [
+    (None, "==="),
+    ("***", "***"),
+    ("", None),
+])
+def test_footer_separator_uses_configured_rule(monkeypatch, configured, expected):
+    monkeypatch.setattr(server, "SEPARATOR", configured)
+
+    assert server._footer_separator("===") == expected
+
+
+@pytest.mark.parametrize("interaction_id", ["", "   ", ".", ".."])
+def test_delete_interaction_rejects_invalid_ids(interaction_id):
+    result = server._delete_interaction(interaction_id)
+
+    assert result == {"error": "invalid interaction_id", "interaction_id": interaction_id, "status": "failed"}
+
+
+@pytest.mark.parametrize("interaction_id, path", [
+    ("abc123", "/interactions/abc123"),
+    ("../files/abc", "/interactions/..%2Ffiles%2Fabc"),
+    ("abc?force=true", "/interactions/abc%3Fforce%3Dtrue"),
+])
+def test_delete_interaction_keeps_id_in_one_path_segment(monkeypatch, interaction_id, path):
+    requested = []
+
+    def handler(request):
+        requested.append(request.url)
+        return httpx.Response(200, json={})
+
+    monkeypatch.setattr(server, "INTERACTIONS_ENDPOINT", "https://api.test/v1beta/interactions")
+    _mock_api(monkeypatch, handler)
+
+    result = server._delete_interaction(interaction_id)
+
+    assert result["status"] == "deleted"
+    assert requested[0].raw_path.decode() == f"/v1beta{path}"
+
+
+def test_delete_interactions_requires_ids():
+    assert server.delete_interactions([]).startswith("Error: ")
+
+
+@pytest.mark.parametrize("thinking_level, max_tokens, warns", [
+    ("high", 8191, True),
+    ("high", 1024, True),
+    ("high", 8192, False),
+    ("medium", 1024, False),
+    ("minimal", 256, False),
+])
+def test_token_budget_warning_threshold(thinking_level, max_tokens, warns):
+    warning = server._token_budget_warning(thinking_level, max_tokens)
+
+    assert (warning is not None) == warns
+    if warns:
+        assert f"max_tokens={max_tokens}" in warning
+
+
+def test_ask_notes_small_budget_for_high_thinking(monkeypatch):
+    _mock_api(monkeypatch, lambda request: httpx.Response(200, json={
+        "id": "abc",
+        "outputs": [{"type": "text", "text": "Short answer"}],
+    }))
+
+    output = server.ask("question", max_tokens=2048, thinking_level="high")
+
+    assert "\nNote: max_tokens=2048 is low for thinking_level=high" in output
+
+
+@pytest.mark.parametrize("text, markers, expected", [
+    ("Paris is the capital.", [(5, 1)], "Paris[1] is the capital."),
+    ("One. Two.", [(4, 1), (9, 2)], "One.[1] Two.[2]"),
+    ("Same spot.", [(10, 2), (10, 1)], "Same spot.[1][2]"),
+    # Offsets are UTF-8 bytes: "café" is 5 bytes long
+    ("café ok", [(5, 1)], "café[1] ok"),
+    # An offset inside a multi-byte character moves past it rather than splitting it
+    ("日本語", [(4, 1)], "日本[1]語"),
+    ("short", [(99, 1), (-1, 2)], "short"),
+    ("untouched", [], "untouched"),
+])
+def test_insert_citation_markers(text, markers, expected):
+    assert server._insert_citation_markers(text, markers) == expected
+
+
+_ANNOTATED_RESPONSE = {
+    "id": "abc",
+    "outputs": [
+        {
+            "type": "text",
+            "text": "URL: https://example.com/a",
+            "annotations": [{"source": "https://example.com/a", "end_index": 26}],
+        },
+        {
+            "type": "google_search_result",
+            "result": [
+                {"url": "https://example.com/a", "title": "Example A"},
+                {"url": "https://example.com/b", "title": "Example B"},
+            ],
+        },
+    ],
+}
+
+
+def test_parse_interaction_response_without_markers():
+    result = server._parse_interaction_response(_ANNOTATED_RESPONSE)
+
+    assert result["text"] == "URL: https://example.com/a"
+
+
+def test_parse_interaction_response_dedupes_sources_by_url():
+    result = server._parse_interaction_response(_ANNOTATED_RESPONSE, citation_markers=True)
+
+    assert result["text"] == "URL: https://example.com/a[1]"
+    assert result["sources"] == [
+        {"url": "https://example.com/a", "title": "Example A"},
+        {"url": "https://example.com/b", "title": "Example B"},
+    ]
+
+
+def test_parse_interaction_response_reuses_number_for_known_url():
+    data = {
+        "outputs": [
+            {"type": "google_search_result", "result": [{"url": "https://example.com/a", "title": "A"}]},
+            {
+                "type": "text",
+                "text": "Fact.",
+                "annotations": [{"source": "https://example.com/a", "end_index": 5}],
+            },
+        ],
+    }
+
+    result = server._parse_interaction_response(data, citation_markers=True)
+
+    assert result["text"] == "Fact.[1]"
+    assert result["sources"] == [{"url": "https://example.com/a", "title": "A"}]
+
+
+_REDIRECT = "https://vertexaisearch.cloud.google.com/grounding-api-redirect/abc"
+
+
+@pytest.mark.parametrize("contents", [
+    "[1, 2]",
+    '"just a string"',
+    "not json",
+    '{"%s": {"url": "https://example.com", "resolved_at": "yesterday"}}' % _REDIRECT,
+    '{"%s": {"resolved_at": 1}}' % _REDIRECT,
+    '{"%s": ["https://example.com", 1]}' % _REDIRECT,
+])
+def test_load_redirect_cache_treats_bad_data_as_empty(isolated_redirect_cache, contents):
+    isolated_redirect_cache.write_text(contents)
+
+    assert server._load_redirect_cache() == {}
+    assert server._cached_redirect(_REDIRECT) is None
+
+
+def test_load_redirect_cache_keeps_fresh_entries(isolated_redirect_cache, monkeypatch):
+    now = 1_000_000.0
+    monkeypatch.setattr(server.time, "time", lambda: now)
+    monkeypatch.setattr(server, "REDIRECT_CACHE_TTL", 100.0)
+    isolated_redirect_cache.write_text(json.dumps({
+        "fresh": {"url": "https://example.com/fresh", "resolved_at": now - 10},
+        "stale": {"url": "https://example.com/stale", "resolved_at": now - 1000},
+    }))
+
+    assert list(server._load_redirect_cache()) == ["fresh"]
+
+
+def test_store_redirect_overwrites_bad_cache_file(isolated_redirect_cache):
+    isolated_redirect_cache.write_text("[1, 2]")
+
+    server._store_redirect(_REDIRECT, "https://example.com/page")
+
+    assert server._cached_redirect(_REDIRECT) == "https://example.com/page"
+    assert json.loads(isolated_redirect_cache.read_text())[_REDIRECT]["url"] == "https://example.com/page"
+    assert [path.name for path in isolated_redirect_cache.parent.iterdir()] == ["redirects.json"]
+
+
+def test_zero_ttl_disables_redirect_cache(isolated_redirect_cache, monkeypatch):
+    monkeypatch.setattr(server, "REDIRECT_CACHE_TTL", 0.0)
+
+    server._store_redirect(_REDIRECT, "https://example.com/page")
+
+    assert server._cached_redirect(_REDIRECT) is None
+    assert not isolated_redirect_cache.exists()
+
+
+@pytest.mark.parametrize("stored, cached", [(True, True), (False, False)])
+def test_unstored_answers_skip_redirect_cache(isolated_redirect_cache, monkeypatch, stored, cached):
+    monkeypatch.setattr(server, "RESOLVE_REDIRECTS", True)
+    monkeypatch.setattr(server, "_fetch_redirect_location", lambda url: "https://example.com/page")
+    result = {"text": "Answer", "sources": [{"url": _REDIRECT, "title": "Page"}], "stored": stored}
+
+    output = server._format_response(result)
+
+    assert "(https://example.com/page)" in output
+    assert isolated_redirect_cache.exists() == cached
+
+
+@pytest.mark.parametrize("text, expected", [
+    ("\x1b[31mred\x1b[0m text", "red text"),
+    ("\x1b]0;window title\x07Answer", "Answer"),
+    ("\x1b]8;;https://example.com\x1b\\link\x1b]8;;\x1b\\", "link"),
+    ("line one\r\nline two\r", "line one\nline two"),
+    ("tab\tkept\nnewline kept", "tab\tkept\nnewline kept"),
+    ("bell\x07 and null\x00 and c1\x9b", "bell and null and c1"),
+    # An unterminated OSC must not swallow the rest of the answer
+    (
+        "Answer part one \x1b]stray osc then more text.\n\n---\nTo follow up, use interaction_id: abc",
+        "Answer part one stray osc then more text.\n\n---\nTo follow up, use interaction_id: abc",
+    ),
+])
+def test_sanitize_output(text, expected):
+    assert server._sanitize_output(text) == expected
+
+
+def test_delete_interactions_sanitizes_api_errors(monkeypatch):
+    monkeypatch.setattr(server, "SANITIZE_OUTPUT", True)
+    _mock_api(monkeypatch, lambda request: httpx.Response(500, text="\x1b[31mboom\x1b[0m"))
+
+    assert server.delete_interactions(["abc"]) == "abc: API error: 500 - boom"
+
+
+def test_ask_labels_tool_only_answers(monkeypatch):
+    _mock_api(monkeypatch, lambda request: httpx.Response(200, json={
+        "id": "abc",
+        "outputs": [
+            {"type": "google_search_call"},
+            {"type": "google_search_result", "result": [{"url": "https://example.com/a", "title": "Example A"}]},
+            {"type": "text", "text": None},
+        ],
+    }))
+
+    output = server.ask("question")
+
+    assert output.startswith("(no text output — tool results only)")
+    assert "Sources:\n1. [Example A](https://example.com/a)" in output
+
+
+def test_ask_retries_without_tools_after_tool_error(monkeypatch):
+    payloads = []
+
+    def handler(request):
+        payloads.append(json.loads(request.content))
+        if len(payloads) == 1:
+            return httpx.Response(400, json={"error": {
+                "code": 400,
+                "message": "google_search is not available in your region",
+                "status": "FAILED_PRECONDITION",
+            }})
+        return httpx.Response(200, json={"id": "abc", "outputs": [{"type": "text", "text": "Ungrounded answer"}]})
+
+    monkeypatch.setattr(server, "RETRY_WITHOUT_TOOLS", True)
+    _mock_api(monkeypatch, handler)
+
+    output = server.ask("question")
+
+    assert len(payloads) == 2
+    assert payloads[0]["tools"] == [{"type": "google_search"}, {"type": "url_context"}]
+    assert payloads[1]["tools"] == []
+    assert output.startswith("Ungrounded answer")
+    assert "\nNote: Grounding tools were unavailable; this answer is not grounded." in output
+
+
+def test_ask_reports_expired_api_key_on_follow_up(monkeypatch):
+    requests = []
+
+    def handler(request):
+        requests.append(request)
+        return httpx.Response(400, json={"error": {
+            "code": 400,
+            "message": "API key expired. Please renew the API key.",
+            "status": "INVALID_ARGUMENT",
+            "details": [{"@type": "type.googleapis.com/google.rpc.ErrorInfo", "reason": "API_KEY_INVALID"}],
+        }})
+
+    _mock_api(monkeypatch, handler)
+
+    output = server.ask("question", interaction_id="abc", restart_if_expired=True)
+
+    assert output.startswith("Error: API error: 400 INVALID_ARGUMENT - API key expired.")
+    assert len(requests) == 1
+
+
+def test_fetch_redirect_location_falls_back_to_get(monkeypatch):
+    methods = []
+
+    def handler(request):
+        methods.append(request.method)
+        if request.method == "HEAD":
+            return httpx.Response(405)
+        return httpx.Response(302, headers={"location": "https://example.com/page"})
+
+    _mock_api(monkeypatch, handler)
+
+    assert server._fetch_redirect_location(_REDIRECT) == "https://example.com/page"
+    assert methods == ["HEAD", "GET"]
+
+
+def test_ask_sends_and_reports_request_ids(monkeypatch):
+    sent_ids = []
+
+    def handler(request):
+        sent_ids.append(request.headers.get("x-request-id"))
+        return httpx.Response(
+            400,
+            headers={"x-request-id": "server-side-id"},
+            json={"error": {"code": 400, "message": "Bad request", "status": "INVALID_ARGUMENT"}},
+        )
+
+    _mock_api(monkeypatch, handler)
+
+    output = server.ask("question")
+
+    assert sent_ids[0]
+    assert f"request id: {sent_ids[0]}, server request id: server-side-id" in output
+    assert output.startswith("Error: ")
+
+
+class _SlowStream(httpx.SyncByteStream):
+    """Body that advances a fake clock by ten seconds per chunk."""
+
+    def __init__(self, clock):
+        self._clock = clock
+
+    def __iter__(self):
+        for _ in range(100):
+            self._clock[0] += 10.0
+            yield b" "
+
+
+def test_max_wall_time_aborts_slow_responses(monkeypatch):
+    clock = [0.0]
+    monkeypatch.setattr(server.time, "monotonic", lambda: clock[0])
+    monkeypatch.setattr(server, "MAX_WALL_TIME", 25.0)
+    _mock_api(monkeypatch, lambda request: httpx.Response(200, stream=_SlowStream(clock)))
+
+    output = server.ask("question")
+
+    assert output.startswith("Error: Request failed: exceeded GEMINI_MAX_WALL_TIME of 25s")
+    assert clock[0] == 30.0
+
+
+def test_max_wall_time_skips_retries_past_the_deadline(monkeypatch):
+    attempts = []
+
+    def handler(request):
+        attempts.append(request)
+        return httpx.Response(503, headers={"retry-after": "60"}, json={"error": {"code": 503, "message": "busy"}})
+
+    monkeypatch.setattr(server, "MAX_WALL_TIME", 30.0)
+    monkeypatch.setattr(server, "MAX_RETRIES", 3)
+    monkeypatch.setattr(server.time, "sleep", lambda seconds: pytest.fail("slept past the deadline"))
+    _mock_api(monkeypatch, handler)
+
+    output = server.ask("question")
+
+    assert output.startswith("Error: API error: 503 - busy")
+    assert len(attempts) == 1
+
+
+def test_max_wall_time_leaves_normal_answers_alone(monkeypatch):
+    monkeypatch.setattr(server, "MAX_WALL_TIME", 30.0)
+    _mock_api(monkeypatch, lambda request: httpx.Response(200, json={
+        "id": "abc",
+        "outputs": [{"type": "text", "text": "Answer"}],
+    }))
+
+    assert server.ask("question").startswith("Answer")
+
+
+def test_parse_interaction_response_counts_tool_usage():
+    data = {
+        "outputs": [
+            {"type": "google_search_call", "arguments": {"queries": ["capital of France", "Paris population"]}},
+            {"type": "google_search_call"},
+            {"type": "url_context_call", "arguments": {"urls": ["https://example.com/a"]}},
+            {"type": "url_context_call", "arguments": {}},
+            {"type": "text", "text": "Answer"},
+        ],
+    }
+
+    result = server._parse_interaction_response(data)
+
+    assert result["tool_usage"] == {"google_search": 3, "url_context": 2}
+
+
+def test_format_response_shows_tool_usage(monkeypatch):
+    monkeypatch.setattr(server, "SHOW_TOOL_USAGE", True)
+    result = {"text": "Answer", "tool_usage": {"google_search": 3, "url_context": 1}, "stored": False}
+
+    assert server._format_response(result) == "Answer\n\nTool usage: google_search queries: 3, url_context fetches: 1"
+
+
+@pytest.mark.parametrize("status_code, attempts", [(409, 2), (503, 1)])
+def test_post_with_retry_uses_configured_statuses(monkeypatch, status_code, attempts):
+    requests = []
+
+    def handler(request):
+        requests.append(request)
+        if len(requests) == 1:
+            return httpx.Response(status_code)
+        return httpx.Response(200, json={})
+
+    monkeypatch.setattr(server, "RETRYABLE_STATUSES", {409})
+    monkeypatch.setattr(server, "MAX_RETRIES", 3)
+    monkeypatch.setattr(server.time, "sleep", lambda seconds: None)
+    with httpx.Client(transport=httpx.MockTransport(handler)) as client:
+        server._post_with_retry(client, {}, {})
+
+    assert len(requests) == attempts
+
+
+@pytest.mark.parametrize("value", ["200", "429,302", "abc", ","])
+def test_retry_on_status_rejects_non_error_codes(monkeypatch, value):
+    monkeypatch.setenv("GEMINI_RETRY_ON_STATUS", value)
+
+    with pytest.raises(ValueError, match="GEMINI_RETRY_ON_STATUS"):
+        _reload_server()
+
+
+def test_retry_on_status_overrides_defaults(monkeypatch):
+    monkeypatch.setenv("GEMINI_RETRY_ON_STATUS", "409, 503")
+
+    assert _reload_server().RETRYABLE_STATUSES == {409, 503}
+
+
+def test_fetch_redirect_location_retries_transient_errors(monkeypatch):
+    attempts = []
+
+    def handler(request):
+        attempts.append(request.method)
+        if len(attempts) == 1:
+            raise httpx.ConnectError("connection reset", request=request)
+        return httpx.Response(302, headers={"location": "https://example.com/page"})
+
+    monkeypatch.setattr(server, "REDIRECT_RETRIES", 1)
+    monkeypatch.setattr(server.time, "sleep", lambda seconds: None)
+    _mock_api(monkeypatch, handler)
+
+    assert server._fetch_redirect_location(_REDIRECT) == "https://example.com/page"
+    assert attempts == ["HEAD", "HEAD"]
+
+
+@pytest.mark.parametrize("thinking_level, expected", [
+    ("minimal", "low"),
+    ("low", "medium"),
+    ("medium", "high"),
+    ("high", "high"),
+])
+def test_next_thinking_level(thinking_level, expected):
+    assert server._next_thinking_level(thinking_level) == expected
+
+
+def test_ask_retries_empty_answers_at_higher_thinking(monkeypatch):
+    payloads = []
+
+    def handler(request):
+        payloads.append(json.loads(request.content))
+        if len(payloads) == 1:
+            return httpx.Response(200, json={"id": "empty", "outputs": [{"type": "text", "text": ""}]})
+        return httpx.Response(200, json={"id": "abc", "outputs": [{"type": "text", "text": "Real answer"}]})
+
+    monkeypatch.setattr(server, "RETRY_EMPTY_ANSWERS", True)
+    _mock_api(monkeypatch, handler)
+
+    output = server.ask("question", thinking_level="medium")
+
+    assert [p["generation_config"]["thinking_level"] for p in payloads] == ["medium", "high"]
+    assert output.startswith("Real answer")
+    assert "\nNote: The first answer was empty; this one was retried at thinking_level=high." in output
+    assert "interaction_id: abc" in output
+
+
+@pytest.mark.parametrize("retry_empty, outputs", [
+    (False, [{"type": "text", "text": ""}]),
+    (True, [{"type": "google_search_result", "result": [{"url": "https://example.com/a", "title": "A"}]}]),
+])
+def test_ask_keeps_answers_without_empty_retry(monkeypatch, retry_empty, outputs):
+    requests = []
+
+    def handler(request):
+        requests.append(request)
+        return httpx.Response(200, json={"id": "abc", "outputs": outputs})
+
+    monkeypatch.setattr(server, "RETRY_EMPTY_ANSWERS", retry_empty)
+    _mock_api(monkeypatch, handler)
+
+    server.ask("question")
+
+    assert len(requests) == 1
+
+
+_USAGE = {
+    "total_input_tokens": 1000,
+    "total_tool_use_tokens": 3000,
+    "total_output_tokens": 500,
+    "total_thought_tokens": 1500,
+}
+
+
+def test_format_usage_footer(monkeypatch):
+    monkeypatch.setattr(server, "SHOW_COST", False)
+
+    assert server._format_usage(_USAGE) == "Tokens: 1000 in / 500 out / 1500 reasoning"
+    assert server._format_usage({}) is None
+
+
+def test_format_usage_estimates_cost_with_tool_tokens(monkeypatch):
+    monkeypatch.setattr(server, "SHOW_COST", True)
+    monkeypatch.setattr(server, "MODEL", "test-model")
+    monkeypatch.setattr(server, "MODEL_PRICING", {"test-model": (0.50, 3.00)})
+
+    # (1000 + 3000) input * $0.50/M + (500 + 1500) output * $3.00/M = $0.0020 + $0.0060
+    assert server._format_usage(_USAGE) == "Tokens: 1000 in / 500 out / 1500 reasoning (~$0.0080)"
+
+
+@pytest.mark.parametrize("pricing", [{}, {"test-model": (None, 3.00)}, {"test-model": (0.50, None)}])
+def test_format_usage_without_complete_pricing(monkeypatch, pricing):
+    monkeypatch.setattr(server, "SHOW_COST", True)
+    monkeypatch.setattr(server, "MODEL", "test-model")
+    monkeypatch.setattr(server, "MODEL_PRICING", pricing)
+
+    assert server._format_usage(_USAGE).endswith("(no pricing for test-model)")
+
+
+def test_partial_price_override_for_unknown_model(monkeypatch):
+    monkeypatch.setenv("GEMINI_MODEL", "custom-model")
+    monkeypatch.setenv("GEMINI_INPUT_PRICE", "0.40")
+    monkeypatch.delenv("GEMINI_OUTPUT_PRICE", raising=False)
+
+    assert _reload_server().MODEL_PRICING["custom-model"] == (0.40, None)
+
+
+def test_http_client_uses_connect_timeout(monkeypatch):
+    monkeypatch.setattr(server, "CONNECT_TIMEOUT", 10.0)
+
+    with server._http_client(120.0) as client:
+        assert client.timeout.connect == 10.0
+        assert client.timeout.read == 120.0
+
+    # A shorter overall timeout also caps the connect phase
+    with server._http_client(5.0) as client:
+        assert client.timeout.connect == 5.0
+        assert client.timeout.read == 5.0